@@ -0,0 +1,529 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2014 Y. T. CHUNG <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Memcached classic text (ASCII) protocol
+
+use std::collections::TreeMap;
+use std::io::{BufferedStream, TcpStream};
+use std::str;
+
+use version::Version;
+
+use proto::{Proto, Operation, MultiOperation, ServerOperation, NoReplyOperation, CasOperation};
+use proto::{MemCachedResult, Error};
+use proto::{MemCachedError, OtherError};
+use proto::{Status, KeyNotFound, KeyExists, ItemNotStored, IncrDecrOnNonNumericValue, NotSupported};
+
+/// Talks to a memcached server over the classic text protocol.
+pub struct AsciiProto {
+    stream: BufferedStream<TcpStream>,
+}
+
+impl AsciiProto {
+    /// Wrap an already connected stream with the text protocol.
+    pub fn new(stream: TcpStream) -> AsciiProto {
+        AsciiProto {
+            stream: BufferedStream::new(stream),
+        }
+    }
+
+    /// Read one `\r\n`-terminated line, with the terminator stripped.
+    fn read_line(&mut self) -> MemCachedResult<String> {
+        let line = try!(self.stream.read_line());
+        Ok(line.as_slice().trim_right_chars(['\r', '\n'].as_slice()).to_string())
+    }
+
+    /// Read exactly `len` data bytes followed by the trailing `\r\n`.
+    fn read_data(&mut self, len: uint) -> MemCachedResult<Vec<u8>> {
+        let data = try!(self.stream.read_exact(len));
+        // Swallow the trailing CRLF after the data block.
+        try!(self.stream.read_exact(2));
+        Ok(data)
+    }
+
+    /// Issue a storage command (`set`/`add`/`replace`/`append`/`prepend`/`cas`)
+    /// and hand back the raw reply line, or nothing when `noreply` was set.
+    fn store(&mut self, cmd: &str, key: &[u8], value: &[u8], flags: u32, expiration: u32,
+             cas: Option<u64>, noreply: bool) -> MemCachedResult<Option<String>> {
+        let keystr = try!(as_key(key));
+        match cas {
+            Some(cas) =>
+                try!(write!(&mut self.stream, "{} {} {} {} {} {}{}\r\n",
+                            cmd, keystr, flags, expiration, value.len(), cas,
+                            if noreply { " noreply" } else { "" })),
+            None =>
+                try!(write!(&mut self.stream, "{} {} {} {} {}{}\r\n",
+                            cmd, keystr, flags, expiration, value.len(),
+                            if noreply { " noreply" } else { "" })),
+        }
+        try!(self.stream.write(value));
+        try!(self.stream.write(b"\r\n"));
+        try!(self.stream.flush());
+
+        if noreply {
+            return Ok(None);
+        }
+        Ok(Some(try!(self.read_line())))
+    }
+
+    /// Turn a storage reply (`STORED`/`NOT_STORED`/`EXISTS`/`NOT_FOUND`) into a
+    /// `MemCachedResult`, mapping failures onto the shared `Status` enum.
+    fn check_store(&self, reply: String) -> MemCachedResult<()> {
+        match reply.as_slice() {
+            "STORED" => Ok(()),
+            "NOT_STORED" => Err(status_error(ItemNotStored)),
+            "EXISTS" => Err(status_error(KeyExists)),
+            "NOT_FOUND" => Err(status_error(KeyNotFound)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Drive `incr`/`decr`, which reply either with the new value or `NOT_FOUND`.
+    fn incr_decr(&mut self, cmd: &str, key: &[u8], amount: u64, noreply: bool)
+            -> MemCachedResult<u64> {
+        let keystr = try!(as_key(key));
+        try!(write!(&mut self.stream, "{} {} {}{}\r\n",
+                    cmd, keystr, amount, if noreply { " noreply" } else { "" }));
+        try!(self.stream.flush());
+
+        if noreply {
+            return Ok(0);
+        }
+
+        let reply = try!(self.read_line());
+        parse_incr_reply(reply.as_slice())
+    }
+
+    /// Run a `get`/`gets` request and collect every `VALUE` line up to `END`.
+    fn retrieve(&mut self, cmd: &str, keys: &[Vec<u8>])
+            -> MemCachedResult<TreeMap<Vec<u8>, (Vec<u8>, u32, u64)>> {
+        try!(write!(&mut self.stream, "{}", cmd));
+        for key in keys.iter() {
+            try!(write!(&mut self.stream, " {}", try!(as_key(key.as_slice()))));
+        }
+        try!(self.stream.write(b"\r\n"));
+        try!(self.stream.flush());
+
+        let mut result = TreeMap::new();
+        loop {
+            let line = try!(self.read_line());
+            if line.as_slice() == "END" {
+                break;
+            }
+
+            let (key, flags, len, cas) = try!(parse_value_line(line.as_slice()));
+            let data = try!(self.read_data(len));
+            result.insert(key, (data, flags, cas));
+        }
+        Ok(result)
+    }
+}
+
+/// A text-protocol key must be valid UTF-8 with no embedded whitespace.
+fn as_key<'a>(key: &'a [u8]) -> MemCachedResult<&'a str> {
+    match str::from_utf8(key) {
+        Some(s) if s.chars().any(|c| c.is_whitespace()) =>
+            Err(Error::new(OtherError, "key contains whitespace", None)),
+        Some(s) => Ok(s),
+        None => Err(Error::new(OtherError, "key is not valid utf-8", None)),
+    }
+}
+
+fn status_error(status: Status) -> Error {
+    Error::new(MemCachedError(status), status.desc(), None)
+}
+
+fn unexpected(line: &str) -> Error {
+    Error::new(OtherError, "unexpected server reply", Some(line.to_string()))
+}
+
+/// Parse a `VALUE <key> <flags> <bytes> [<cas>]` header line into its fields.
+fn parse_value_line(line: &str) -> MemCachedResult<(Vec<u8>, u32, uint, u64)> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.len() < 4 || parts[0] != "VALUE" {
+        return Err(unexpected(line));
+    }
+
+    let key = parts[1].as_bytes().to_vec();
+    let flags: u32 = match from_str(parts[2]) {
+        Some(f) => f,
+        None => return Err(unexpected(line)),
+    };
+    let len: uint = match from_str(parts[3]) {
+        Some(l) => l,
+        None => return Err(unexpected(line)),
+    };
+    let cas: u64 = if parts.len() >= 5 {
+        from_str(parts[4]).unwrap_or(0)
+    } else {
+        0
+    };
+    Ok((key, flags, len, cas))
+}
+
+/// Classify an `incr`/`decr` reply line: the new value, or the matching status.
+fn parse_incr_reply(reply: &str) -> MemCachedResult<u64> {
+    match reply {
+        "NOT_FOUND" => Err(status_error(KeyNotFound)),
+        "CLIENT_ERROR cannot increment or decrement non-numeric value" =>
+            Err(status_error(IncrDecrOnNonNumericValue)),
+        value => match from_str::<u64>(value) {
+            Some(v) => Ok(v),
+            None => Err(unexpected(value)),
+        },
+    }
+}
+
+impl Operation for AsciiProto {
+    fn set(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        let reply = try!(self.store("set", key, value, flags, expiration, None, false));
+        self.check_store(reply.unwrap())
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        let reply = try!(self.store("add", key, value, flags, expiration, None, false));
+        self.check_store(reply.unwrap())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> MemCachedResult<()> {
+        let keystr = try!(as_key(key));
+        try!(write!(&mut self.stream, "delete {}\r\n", keystr));
+        try!(self.stream.flush());
+        match try!(self.read_line()).as_slice() {
+            "DELETED" => Ok(()),
+            "NOT_FOUND" => Err(status_error(KeyNotFound)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn replace(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        let reply = try!(self.store("replace", key, value, flags, expiration, None, false));
+        self.check_store(reply.unwrap())
+    }
+
+    fn get(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32)> {
+        let keys = [key.to_vec()];
+        let mut result = try!(self.retrieve("get", keys.as_slice()));
+        match result.pop(&key.to_vec()) {
+            Some((data, flags, _)) => Ok((data, flags)),
+            None => Err(status_error(KeyNotFound)),
+        }
+    }
+
+    fn getk(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32)> {
+        let (data, flags) = try!(self.get(key));
+        Ok((key.to_vec(), data, flags))
+    }
+
+    fn increment(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64> {
+        match self.incr_decr("incr", key, amount, false) {
+            Err(Error { kind: MemCachedError(KeyNotFound), .. }) => {
+                // Seed the counter the way the binary protocol's initial value does.
+                try!(self.add(key, initial.to_string().as_bytes(), 0, expiration));
+                Ok(initial)
+            }
+            other => other,
+        }
+    }
+
+    fn decrement(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64> {
+        match self.incr_decr("decr", key, amount, false) {
+            Err(Error { kind: MemCachedError(KeyNotFound), .. }) => {
+                try!(self.add(key, initial.to_string().as_bytes(), 0, expiration));
+                Ok(initial)
+            }
+            other => other,
+        }
+    }
+
+    fn append(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        let reply = try!(self.store("append", key, value, 0, 0, None, false));
+        self.check_store(reply.unwrap())
+    }
+
+    fn prepend(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        let reply = try!(self.store("prepend", key, value, 0, 0, None, false));
+        self.check_store(reply.unwrap())
+    }
+
+    fn touch(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<()> {
+        let keystr = try!(as_key(key));
+        try!(write!(&mut self.stream, "touch {} {}\r\n", keystr, expiration));
+        try!(self.stream.flush());
+        match try!(self.read_line()).as_slice() {
+            "TOUCHED" => Ok(()),
+            "NOT_FOUND" => Err(status_error(KeyNotFound)),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+impl CasOperation for AsciiProto {
+    fn set_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64)
+            -> MemCachedResult<u64> {
+        let reply = try!(self.store("cas", key, value, flags, expiration, Some(cas), false));
+        try!(self.check_store(reply.unwrap()));
+        // The store itself was guarded by `cas`, but the text protocol never
+        // echoes the cas unique it assigned, so we re-read with `gets`. This is
+        // a TOCTOU: another client may write between the store and the re-read,
+        // in which case the cas returned here belongs to that write, not to
+        // ours. Treat the returned value as advisory, not authoritative.
+        let (_, _, cas) = try!(self.get_cas(key));
+        Ok(cas)
+    }
+
+    fn add_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<u64> {
+        try!(self.add(key, value, flags, expiration));
+        // Advisory cas only: the re-read is not atomic with the add (see `set_cas`).
+        let (_, _, cas) = try!(self.get_cas(key));
+        Ok(cas)
+    }
+
+    fn replace_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64)
+            -> MemCachedResult<u64> {
+        let reply = try!(self.store("cas", key, value, flags, expiration, Some(cas), false));
+        try!(self.check_store(reply.unwrap()));
+        // Advisory cas only: the re-read is not atomic with the store (see `set_cas`).
+        let (_, _, cas) = try!(self.get_cas(key));
+        Ok(cas)
+    }
+
+    fn get_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32, u64)> {
+        let keys = [key.to_vec()];
+        let mut result = try!(self.retrieve("gets", keys.as_slice()));
+        match result.pop(&key.to_vec()) {
+            Some((data, flags, cas)) => Ok((data, flags, cas)),
+            None => Err(status_error(KeyNotFound)),
+        }
+    }
+
+    fn getk_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32, u64)> {
+        let (data, flags, cas) = try!(self.get_cas(key));
+        Ok((key.to_vec(), data, flags, cas))
+    }
+
+    fn increment_cas(&mut self, _key: &[u8], _amount: u64, _initial: u64, _expiration: u32, _cas: u64)
+            -> MemCachedResult<(u64, u64)> {
+        // The text protocol's `incr` takes no cas unique, so a cas-guarded
+        // increment cannot be honored atomically. Rather than silently drop the
+        // cas and risk clobbering a concurrently-modified value, refuse it.
+        Err(status_error(NotSupported))
+    }
+
+    fn decrement_cas(&mut self, _key: &[u8], _amount: u64, _initial: u64, _expiration: u32, _cas: u64)
+            -> MemCachedResult<(u64, u64)> {
+        // Likewise `decr` cannot carry a cas unique in the text protocol.
+        Err(status_error(NotSupported))
+    }
+
+    fn append_cas(&mut self, _key: &[u8], _value: &[u8], _cas: u64) -> MemCachedResult<u64> {
+        // The text protocol's `append` grammar has no cas-unique field (only `cas`
+        // does), so there is no way to append atomically against a cas value.
+        Err(status_error(NotSupported))
+    }
+
+    fn prepend_cas(&mut self, _key: &[u8], _value: &[u8], _cas: u64) -> MemCachedResult<u64> {
+        // Likewise `prepend` cannot carry a cas unique in the text protocol.
+        Err(status_error(NotSupported))
+    }
+
+    fn touch_cas(&mut self, _key: &[u8], _expiration: u32, _cas: u64) -> MemCachedResult<u64> {
+        // The text protocol's `touch` takes no cas unique either.
+        Err(status_error(NotSupported))
+    }
+}
+
+impl ServerOperation for AsciiProto {
+    fn quit(&mut self) -> MemCachedResult<()> {
+        try!(self.stream.write(b"quit\r\n"));
+        try!(self.stream.flush());
+        Ok(())
+    }
+
+    fn flush(&mut self, expiration: u32) -> MemCachedResult<()> {
+        try!(write!(&mut self.stream, "flush_all {}\r\n", expiration));
+        try!(self.stream.flush());
+        match try!(self.read_line()).as_slice() {
+            "OK" => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn noop(&mut self) -> MemCachedResult<()> {
+        // The text protocol has no no-op; `version` is the cheapest round-trip.
+        self.version().map(|_| ())
+    }
+
+    fn version(&mut self) -> MemCachedResult<Version> {
+        try!(self.stream.write(b"version\r\n"));
+        try!(self.stream.flush());
+        let line = try!(self.read_line());
+        let parts: Vec<&str> = line.as_slice().split(' ').collect();
+        if parts.len() < 2 || parts[0] != "VERSION" {
+            return Err(unexpected(line.as_slice()));
+        }
+        match Version::parse(parts[1]) {
+            Some(v) => Ok(v),
+            None => Err(unexpected(line.as_slice())),
+        }
+    }
+
+    fn stat(&mut self) -> MemCachedResult<TreeMap<String, String>> {
+        try!(self.stream.write(b"stats\r\n"));
+        try!(self.stream.flush());
+
+        let mut result = TreeMap::new();
+        loop {
+            let line = try!(self.read_line());
+            if line.as_slice() == "END" {
+                break;
+            }
+            let parts: Vec<&str> = line.as_slice().splitn(2, ' ').collect();
+            if parts.len() < 3 || parts[0] != "STAT" {
+                return Err(unexpected(line.as_slice()));
+            }
+            result.insert(parts[1].to_string(), parts[2].to_string());
+        }
+        Ok(result)
+    }
+}
+
+impl MultiOperation for AsciiProto {
+    fn set_multi(&mut self, kv: TreeMap<Vec<u8>, (Vec<u8>, u32, u32)>) -> MemCachedResult<()> {
+        for (key, (value, flags, expiration)) in kv.into_iter() {
+            try!(self.set(key.as_slice(), value.as_slice(), flags, expiration));
+        }
+        Ok(())
+    }
+
+    fn delete_multi(&mut self, keys: Vec<Vec<u8>>) -> MemCachedResult<()> {
+        for key in keys.iter() {
+            try!(self.delete(key.as_slice()));
+        }
+        Ok(())
+    }
+
+    fn get_multi(&mut self, keys: Vec<Vec<u8>>) -> MemCachedResult<TreeMap<Vec<u8>, (Vec<u8>, u32)>> {
+        let result = try!(self.retrieve("get", keys.as_slice()));
+        let mut collected = TreeMap::new();
+        for (key, (data, flags, _)) in result.into_iter() {
+            collected.insert(key, (data, flags));
+        }
+        Ok(collected)
+    }
+}
+
+impl NoReplyOperation for AsciiProto {
+    fn set_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.store("set", key, value, flags, expiration, None, true).map(|_| ())
+    }
+
+    fn add_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.store("add", key, value, flags, expiration, None, true).map(|_| ())
+    }
+
+    fn delete_noreply(&mut self, key: &[u8]) -> MemCachedResult<()> {
+        let keystr = try!(as_key(key));
+        try!(write!(&mut self.stream, "delete {} noreply\r\n", keystr));
+        try!(self.stream.flush());
+        Ok(())
+    }
+
+    fn replace_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.store("replace", key, value, flags, expiration, None, true).map(|_| ())
+    }
+
+    fn increment_noreply(&mut self, key: &[u8], amount: u64, _initial: u64, _expiration: u32) -> MemCachedResult<()> {
+        self.incr_decr("incr", key, amount, true).map(|_| ())
+    }
+
+    fn decrement_noreply(&mut self, key: &[u8], amount: u64, _initial: u64, _expiration: u32) -> MemCachedResult<()> {
+        self.incr_decr("decr", key, amount, true).map(|_| ())
+    }
+
+    fn append_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        self.store("append", key, value, 0, 0, None, true).map(|_| ())
+    }
+
+    fn prepend_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        self.store("prepend", key, value, 0, 0, None, true).map(|_| ())
+    }
+}
+
+impl Proto for AsciiProto {
+    fn clone(&self) -> Box<Proto + Send> {
+        box AsciiProto::new(self.stream.get_ref().clone()) as Box<Proto + Send>
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_value_line, parse_incr_reply};
+    use proto::{Error, MemCachedError, KeyNotFound, IncrDecrOnNonNumericValue};
+
+    #[test]
+    fn value_line_with_cas() {
+        let (key, flags, len, cas) = parse_value_line("VALUE foo 7 3 42").unwrap();
+        assert_eq!(key, b"foo".to_vec());
+        assert_eq!(flags, 7);
+        assert_eq!(len, 3);
+        assert_eq!(cas, 42);
+    }
+
+    #[test]
+    fn value_line_without_cas_defaults_to_zero() {
+        let (key, flags, len, cas) = parse_value_line("VALUE bar 0 5").unwrap();
+        assert_eq!(key, b"bar".to_vec());
+        assert_eq!(flags, 0);
+        assert_eq!(len, 5);
+        assert_eq!(cas, 0);
+    }
+
+    #[test]
+    fn value_line_rejects_end_and_garbage() {
+        assert!(parse_value_line("END").is_err());
+        assert!(parse_value_line("VALUE foo x 3").is_err());
+    }
+
+    #[test]
+    fn incr_reply_not_found_maps_to_key_not_found() {
+        let matched = match parse_incr_reply("NOT_FOUND") {
+            Err(Error { kind: MemCachedError(KeyNotFound), .. }) => true,
+            _ => false,
+        };
+        assert!(matched);
+    }
+
+    #[test]
+    fn incr_reply_non_numeric_maps_to_status() {
+        let line = "CLIENT_ERROR cannot increment or decrement non-numeric value";
+        let matched = match parse_incr_reply(line) {
+            Err(Error { kind: MemCachedError(IncrDecrOnNonNumericValue), .. }) => true,
+            _ => false,
+        };
+        assert!(matched);
+    }
+
+    #[test]
+    fn incr_reply_numeric_value() {
+        assert_eq!(parse_incr_reply("42").unwrap(), 42);
+    }
+}
@@ -21,6 +21,7 @@
 
 //! Memcached protocol
 
+use std::error;
 use std::fmt::{Show, Formatter, mod};
 use std::collections::TreeMap;
 use std::io;
@@ -28,9 +29,12 @@ use std::io;
 use version;
 
 pub use self::binary::BinaryProto;
+pub use self::ascii::AsciiProto;
 
 mod binarydef;
 mod binary;
+mod sasl;
+mod ascii;
 
 /// Memcached response status
 #[deriving(Clone, Show, Eq, PartialEq)]
@@ -123,8 +127,24 @@ impl Status {
 }
 
 /// Protocol type
+#[deriving(Clone)]
 pub enum ProtoType {
     Binary,
+    Ascii,
+}
+
+/// Dial `addr` and return a boxed protocol object speaking `proto_type`.
+///
+/// Lets application code pick the wire protocol from configuration without
+/// naming a concrete implementation; the boxed object's `clone` preserves the
+/// chosen protocol.
+pub fn connect<A: io::net::ip::ToSocketAddr>(addr: A, proto_type: ProtoType)
+        -> MemCachedResult<Box<Proto + Send>> {
+    let stream = try!(io::TcpStream::connect(addr));
+    Ok(match proto_type {
+        Binary => box BinaryProto::new(stream) as Box<Proto + Send>,
+        Ascii => box AsciiProto::new(stream) as Box<Proto + Send>,
+    })
 }
 
 #[deriving(Clone)]
@@ -162,10 +182,43 @@ impl Show for Error {
     }
 }
 
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        self.desc
+    }
+
+    fn detail(&self) -> Option<String> {
+        self.detail.clone()
+    }
+}
+
+impl error::FromError<io::IoError> for Error {
+    fn from_error(err: io::IoError) -> Error {
+        Error::new(IoError(err.kind), err.desc, err.detail)
+    }
+}
+
+impl error::FromError<Status> for Error {
+    fn from_error(status: Status) -> Error {
+        Error::new(MemCachedError(status), status.desc(), None)
+    }
+}
+
 pub trait Proto: Operation + MultiOperation + ServerOperation + NoReplyOperation + CasOperation {
     fn clone(&self) -> Box<Proto + Send>;
 }
 
+/// SASL authentication over the binary protocol.
+///
+/// Kept as a companion trait rather than a `Proto` supertrait because the text
+/// protocol has no SASL handshake; only backends that speak binary implement it.
+pub trait Authentication {
+    /// List the SASL mechanisms the server advertises (SASL List Mechs, `0x20`).
+    fn list_mechanisms(&mut self) -> MemCachedResult<Vec<String>>;
+    /// Authenticate with the `PLAIN` mechanism (SASL Auth, `0x21`).
+    fn auth(&mut self, username: &str, password: &str) -> MemCachedResult<()>;
+}
+
 pub trait Operation {
     fn set(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()>;
     fn add(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()>;